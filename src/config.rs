@@ -0,0 +1,204 @@
+// Runtime configuration for pad mapping, MIDI channel, gate length, and
+// velocity response, loaded from an optional TOML file so forex can be
+// adapted to different drum controllers and musical contexts.
+
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// How many toms forex knows how to drive; any mapping that resolves outside
+/// this range is dropped rather than indexed.
+const TOM_COUNT: usize = 6;
+
+/// How long a note stays on before its note-off fires.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Gate {
+    /// Derived from the current tempo; meaningless without a JACK transport,
+    /// so the midir backend falls back to `FALLBACK_BPM`.
+    Beats { beats: f32 },
+    /// A fixed gate length, independent of tempo
+    Millis { millis: u64 },
+}
+
+/// Tempo assumed for `Gate::Beats` when there's no JACK transport to read it from.
+pub const FALLBACK_BPM: f64 = 120.0;
+
+impl Default for Gate {
+    fn default() -> Self {
+        Gate::Beats { beats: 1.0 }
+    }
+}
+
+impl Gate {
+    /// Resolve this gate to a length in microseconds, given the current
+    /// tempo. Falls back to `FALLBACK_BPM` if `beats_per_minute` is zero or
+    /// negative (no JACK timebase master reports `0.0`), rather than
+    /// dividing by zero and producing an infinite/`u64::MAX` gate.
+    pub fn to_usecs(&self, beats_per_minute: f64) -> u64 {
+        match *self {
+            Gate::Beats { beats } => {
+                let beats_per_minute = if beats_per_minute > 0.0 { beats_per_minute } else { FALLBACK_BPM };
+                ((60.0 / beats_per_minute) * beats as f64 * 1_000_000.0) as u64
+            }
+            Gate::Millis { millis } => millis * 1_000,
+        }
+    }
+}
+
+/// Maps a raw hit velocity (a gilrs axis value, -1.0..=1.0) to a MIDI
+/// velocity (0..=127). Only the magnitude counts, inverted: an axis value of
+/// 0.0 is the hardest hit.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum VelocityCurve {
+    Linear,
+    Exponential { sensitivity: f32 },
+    Logarithmic { sensitivity: f32 },
+}
+
+impl Default for VelocityCurve {
+    fn default() -> Self {
+        VelocityCurve::Linear
+    }
+}
+
+impl VelocityCurve {
+    pub fn apply(&self, velocity: f32) -> u8 {
+        let linear = (1.0 - velocity.abs()).clamp(0.0, 1.0);
+
+        let shaped = match *self {
+            VelocityCurve::Linear => linear,
+            VelocityCurve::Exponential { sensitivity } => linear.powf(sensitivity),
+            VelocityCurve::Logarithmic { sensitivity } => {
+                (1.0 + linear * sensitivity).ln() / (1.0 + sensitivity).ln()
+            }
+        };
+
+        (shaped.clamp(0.0, 1.0) * 127.0) as u8
+    }
+}
+
+fn default_channel() -> u8 { 0 }
+fn default_base_note() -> u8 { 36 }
+fn default_button_code_base() -> u32 { 65824 }
+fn default_axis_code_base() -> u32 { 196608 }
+
+// Matches the axis remap this forex has always shipped with: 0, 1 & 4 keep
+// their position, 3 & 5 swap, and 6 lands on 2. Axis 2 has no tom of its own
+// and lands on tom 0; axis codes past the end of the table are dropped.
+fn default_axis_remap() -> Vec<u8> {
+    vec![0, 1, 0, 5, 4, 3, 2]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// MIDI channel (0-15) to send note on/off messages on
+    #[serde(default = "default_channel")]
+    pub channel: u8,
+
+    /// Note number for tom 0; other toms default to `base_note + tom_id`
+    #[serde(default = "default_base_note")]
+    pub base_note: u8,
+
+    /// Explicit note number per tom, overriding `base_note` entirely
+    #[serde(default)]
+    pub tom_notes: Option<[u8; 6]>,
+
+    /// Subtracted from a gilrs button code to get a tom index
+    #[serde(default = "default_button_code_base")]
+    pub button_code_base: u32,
+
+    /// Subtracted from a gilrs axis code before looking it up in `axis_remap`
+    #[serde(default = "default_axis_code_base")]
+    pub axis_code_base: u32,
+
+    /// Remaps a raw axis index (after subtracting `axis_code_base`) to the
+    /// tom it actually drives
+    #[serde(default = "default_axis_remap")]
+    pub axis_remap: Vec<u8>,
+
+    #[serde(default)]
+    pub gate: Gate,
+
+    #[serde(default)]
+    pub velocity_curve: VelocityCurve,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            channel: default_channel(),
+            base_note: default_base_note(),
+            tom_notes: None,
+            button_code_base: default_button_code_base(),
+            axis_code_base: default_axis_code_base(),
+            axis_remap: default_axis_remap(),
+            gate: Gate::default(),
+            velocity_curve: VelocityCurve::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load from a TOML file; falls back to defaults if no path is given, or
+    /// logs and falls back to defaults if the file can't be read or parsed.
+    pub fn load(path: Option<&Path>) -> Self {
+        let path = match path {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                log::error!("failed to read config file {}: {}", path.display(), err);
+                return Self::default();
+            }
+        };
+
+        match toml::from_str(&contents) {
+            Ok(config) => Self::mask_channel(config),
+            Err(err) => {
+                log::error!("failed to parse config file {}: {}", path.display(), err);
+                Self::default()
+            }
+        }
+    }
+
+    /// Mask an out-of-range MIDI channel down to 0-15 rather than letting it
+    /// spill into the status nibble when OR'd into a note on/off byte.
+    fn mask_channel(mut config: Self) -> Self {
+        if config.channel > 0x0F {
+            log::warn!("configured channel {} is outside 0-15, masking to {}", config.channel, config.channel & 0x0F);
+            config.channel &= 0x0F;
+        }
+        config
+    }
+
+    /// Note number to use for a hit on this tom. Saturates rather than
+    /// panicking if `base_note` is configured close enough to 255 that
+    /// adding `tom_id` would otherwise overflow a `u8`.
+    pub fn note_for(&self, tom_id: u8) -> u8 {
+        match self.tom_notes {
+            Some(notes) => notes[tom_id as usize],
+            None => self.base_note.saturating_add(tom_id),
+        }
+    }
+
+    /// Which tom a gilrs button code maps to, or `None` if it resolves
+    /// outside the 6 toms we know about (e.g. a second controller, or a
+    /// misconfigured `button_code_base`).
+    pub fn tom_for_button(&self, code: u32) -> Option<usize> {
+        let index = code.checked_sub(self.button_code_base)? as usize;
+        Some(index).filter(|index| *index < TOM_COUNT)
+    }
+
+    /// Which tom a gilrs axis code maps to, after remapping, or `None` if
+    /// the code or the remapped value falls outside the 6 toms we know about.
+    pub fn tom_for_axis(&self, code: u32) -> Option<usize> {
+        let index = code.checked_sub(self.axis_code_base)? as usize;
+        let tom = *self.axis_remap.get(index)? as usize;
+        Some(tom).filter(|tom| *tom < TOM_COUNT)
+    }
+}
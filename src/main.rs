@@ -1,23 +1,36 @@
 
 extern crate jack;
 
+use gilrs::{Gilrs, Event, EventType};
 use std::sync::{Arc, Mutex};
-use gilrs::{Gilrs, Event};
 use std::time::SystemTime;
 use std::io;
+use std::thread;
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
 
-#[derive(Debug)]
+mod config;
+mod midi;
+mod sampler;
+
+use config::Config;
+use midi::{JackSink, MidirSink, NoteScheduler};
+
+// How many gilrs events we're willing to buffer between the poller thread and
+// the realtime audio thread before we start dropping the oldest ones.
+const EVENT_QUEUE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
 struct Hit {
     tom_id: u8,
     velocity: f32,
 }
 
 impl Hit {
-    fn to_midi_bytes(&self, channel: u8) -> [u8; 3] {
-        let note = self.tom_id as u8 + 36;
-        let velocity = ((1.0 - self.velocity.abs()) * 127.0) as u8;
+    fn to_midi_bytes(&self, status: u8, config: &Config) -> [u8; 3] {
+        let note = config.note_for(self.tom_id);
+        let velocity = config.velocity_curve.apply(self.velocity);
 
-        [ channel, note, velocity ]
+        [ status, note, velocity ]
     }
 }
 
@@ -56,133 +69,293 @@ impl Tom {
  */
 struct Drums {
     toms: [Tom; 6],
+    config: Arc<Config>,
 }
 
 impl Drums {
+    fn new(config: Arc<Config>) -> Self {
+        Self {
+            toms: [Tom::new(0), Tom::new(1), Tom::new(2), Tom::new(3), Tom::new(4), Tom::new(5)],
+            config,
+        }
+    }
+
+    // Clear any held-down state so a reconnect doesn't inherit a stale hit
+    fn reset(&mut self) {
+        for tom in self.toms.iter_mut() {
+            tom.hit = false;
+        }
+    }
+
     fn process_event(&mut self, event: gilrs::EventType) -> Option<Hit> {
         match event {
             gilrs::EventType::ButtonPressed(_, code) => {
-                let index = code.into_u32() - 65824;
-                self.toms[index as usize].hit();
+                match self.config.tom_for_button(code.into_u32()) {
+                    Some(index) => self.toms[index].hit(),
+                    None => log::warn!("button code {} maps outside the known toms, dropping hit", code.into_u32()),
+                }
                 None
             },
             gilrs::EventType::AxisChanged(_, velocity, code) => {
-                let weird_index = code.into_u32() - 196608;
-                let index = match weird_index {
-                    0 | 1 | 4 => weird_index,
-                    3 => 5,
-                    5 => 3,
-                    6 => 2,
-                    _ => 0,
-                };
-                self.toms[index as usize].record_velocity(velocity)
+                match self.config.tom_for_axis(code.into_u32()) {
+                    Some(index) => self.toms[index].record_velocity(velocity),
+                    None => {
+                        log::warn!("axis code {} maps outside the known toms, dropping hit", code.into_u32());
+                        None
+                    }
+                }
             }
             _ => None,
         }
     }
 }
 
+// Poll gilrs for events on a dedicated thread and forward them over a bounded
+// channel. This keeps udev syscalls and any locking off the JACK realtime
+// thread, which must never block.
+fn spawn_gilrs_poller() -> Receiver<(EventType, SystemTime)> {
+    let (sender, receiver) = bounded(EVENT_QUEUE_SIZE);
+
+    thread::spawn(move || {
+        let mut gilrs = loop {
+            match Gilrs::new() {
+                Ok(gilrs) => break gilrs,
+                Err(err) => {
+                    log::error!("failed to initialize gilrs ({}), retrying in 1s", err);
+                    thread::sleep(std::time::Duration::from_secs(1));
+                }
+            }
+        };
+        let mut xruns: u64 = 0;
+
+        loop {
+            match gilrs.next_event() {
+                Some(Event { id: _, event, time }) => {
+                    if let Err(TrySendError::Full((event, time))) = sender.try_send((event, time)) {
+                        // Consumer can't keep up; drop the oldest buffered event to
+                        // make room rather than blocking the poller.
+                        xruns += 1;
+                        log::warn!("dropped {} gilrs event(s), receiver full", xruns);
+                        let _ = receiver.try_recv();
+                        let _ = sender.try_send((event, time));
+                    }
+                }
+                None => thread::sleep(std::time::Duration::from_millis(1)),
+            }
+        }
+    });
+
+    receiver
+}
+
 /*
  * This is our jack process handler
  */
 struct Processor {
-    // Gilrs contains a raw pointer to a udev_monitor, wrap it for thread safety
-    gilrs: Arc<Mutex<Gilrs>>,
+    gilrs_events: Receiver<(EventType, SystemTime)>,
     drums: Drums,
-    cache: Vec<(u64, Hit)>,
+    scheduler: NoteScheduler,
+    midi: JackSink,
     output: jack::Port<jack::MidiOut>,
+    sampler_hits: Option<Sender<Hit>>,
+    config: Arc<Config>,
 }
 
 impl Processor {
-    fn new(client: &jack::Client) -> Self {
-        let drums = Drums {
-            toms: [Tom::new(0), Tom::new(1), Tom::new(2), Tom::new(3), Tom::new(4), Tom::new(5)],
-        };
-
-        let gilrs = Arc::new(Mutex::new(Gilrs::new().unwrap()));
-        let output = client.register_port("output", jack::MidiOut::default()).unwrap();
+    fn new(client: &jack::Client, sampler_hits: Option<Sender<Hit>>, config: Arc<Config>) -> Result<Self, jack::Error> {
+        let drums = Drums::new(Arc::clone(&config));
+        let gilrs_events = spawn_gilrs_poller();
+        let output = client.register_port("output", jack::MidiOut::default())?;
+        let scheduler = NoteScheduler::new(Arc::clone(&config));
 
-        Self { gilrs, drums, cache: vec![], output }
+        Ok(Self { gilrs_events, drums, scheduler, midi: JackSink::new(), output, sampler_hits, config })
     }
 }
 
-// As we totally really did wrap all our thread unsafe stuff in processor, mark it as thread safe
-unsafe impl Send for Processor {}
-unsafe impl Sync for Processor {}
-
 impl jack::ProcessHandler for Processor {
     fn process(&mut self, client: &jack::Client, process_scope: &jack::ProcessScope) -> jack::Control {
         // Get output midi port writer & transport info
         let mut writer = self.output.writer(process_scope);
         let (_, pos) = client.transport_query();
-        let cycle_times = process_scope.cycle_times().unwrap();
-
-        let mut output: Vec<(u32, [u8; 3])> = vec![];
-
-        while let Some(Event { id: _, event, time }) = self.gilrs.lock().unwrap().next_event() {
-
-            if let Some(hit) = self.drums.process_event(event) {
-                // Get some information about our current cycle & message
-                let hit_usecs_ago = SystemTime::now().duration_since(time).unwrap().as_micros();
 
-                // Calculate when event occurred in jack time, send out midi
-                let hit_frames_ago = ((hit_usecs_ago as f32 / cycle_times.period_usecs as f32) * process_scope.n_frames() as f32) as u32;
-                let hit_frame = process_scope.n_frames() - hit_frames_ago;
-
-                // Check cache for note_offs of same tom, remove & play them before new note_on
-                self.cache.retain(|(_, old_hit)| {
-                    let is_same_tom = hit.tom_id == old_hit.tom_id;
+        // A cycle can be missed once in a while; just wait for the next one
+        let cycle_times = match process_scope.cycle_times() {
+            Ok(cycle_times) => cycle_times,
+            Err(_) => return jack::Control::Continue,
+        };
 
-                    if is_same_tom {
-                        // Output note on message
-                        output.push((hit_frame, old_hit.to_midi_bytes(0x80)));
+        while let Ok((event, time)) = self.gilrs_events.try_recv() {
+            match event {
+                gilrs::EventType::Connected => log::info!("controller connected"),
+                gilrs::EventType::Disconnected => {
+                    log::info!("controller disconnected, clearing pad state");
+                    self.drums.reset();
+                    self.scheduler.flush_all(cycle_times.current_usecs, &mut self.midi);
+                }
+                _ => if let Some(hit) = self.drums.process_event(event) {
+                    // Also route the hit to the cpal sampler, alongside MIDI out, if enabled
+                    if let Some(sampler_hits) = &self.sampler_hits {
+                        let _ = sampler_hits.try_send(hit);
                     }
 
-                    ! is_same_tom
-                });
+                    // How long ago the hit happened, in jack's own usec clock
+                    let hit_usecs_ago = SystemTime::now().duration_since(time).unwrap().as_micros() as u64;
+                    let now_usec = cycle_times.current_usecs.saturating_sub(hit_usecs_ago);
 
-                // Output note on message
-                output.push((hit_frame, hit.to_midi_bytes(0x90)));
+                    let gate_usecs = self.config.gate.to_usecs(pos.beats_per_minute);
 
-                // Cache note off message that will trigger after 1 beat, calculated from jack transport
-                let beat_usecs = ((60.0 / pos.beats_per_minute) * 1000_000.0) as u64;
-                let note_off_usec = cycle_times.current_usecs - hit_usecs_ago as u64 + beat_usecs;
-                self.cache.push((note_off_usec, hit));
+                    self.scheduler.trigger(hit, now_usec, gate_usecs, &mut self.midi);
+                },
             }
         }
 
-        // Output cached notes that have to be output
-        self.cache.retain(|(usec, hit)| {
-            let should_output = *usec >= cycle_times.current_usecs && *usec < cycle_times.next_usecs;
+        // Send any note-offs whose gate has elapsed by now
+        self.scheduler.drain_due(cycle_times.current_usecs, &mut self.midi);
 
-            if should_output {
-                let frame = client.time_to_frames(*usec) - cycle_times.current_frames;
+        // Write everything queued this cycle into the MIDI buffer
+        self.midi.flush(client, &cycle_times, &mut writer);
 
-                output.push((frame, hit.to_midi_bytes(0x80)));
-            }
+        jack::Control::Continue
+    }
+}
 
-            ! should_output
-        });
+enum Backend {
+    Jack,
+    Midir,
+}
+
+struct Args {
+    backend: Backend,
+    config_path: Option<std::path::PathBuf>,
+    sampler: bool,
+}
 
-        // Output all the things we have to output, sorted by time as jack will crash when
-        output.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
-        for (frame, bytes) in output {
-            writer.write(&jack::RawMidi { time: frame, bytes: &bytes });
+// Pick the MIDI backend, config file & whether to enable the cpal sampler from the command line
+fn parse_args() -> Args {
+    let mut args = Args { backend: Backend::Jack, config_path: None, sampler: false };
+    let mut rest = std::env::args().skip(1);
+
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--midir" => args.backend = Backend::Midir,
+            "--config" => args.config_path = rest.next().map(std::path::PathBuf::from),
+            "--sampler" => args.sampler = true,
+            _ => log::warn!("ignoring unknown argument: {}", arg),
         }
+    }
 
-        jack::Control::Continue
+    args
+}
+
+// Spawn the internal cpal sampler if the user opted in with `--sampler`,
+// keeping the stream alive in `_sampler_stream` for the program's lifetime.
+// Never panics: a missing/unusable audio device just means no sampler output.
+fn spawn_sampler_if_enabled(enabled: bool) -> (Option<Sender<Hit>>, Option<cpal::Stream>) {
+    if !enabled {
+        return (None, None);
+    }
+
+    let (sampler_hits_tx, sampler_hits_rx) = bounded(EVENT_QUEUE_SIZE);
+
+    match sampler::spawn(sampler_hits_rx) {
+        Ok(stream) => (Some(sampler_hits_tx), Some(stream)),
+        Err(err) => {
+            log::error!("failed to start cpal sampler, continuing without it: {}", err);
+            (None, None)
+        }
     }
 }
 
-fn main() {
-    let (client, _status) = jack::Client::new("Forex", jack::ClientOptions::NO_START_SERVER).unwrap();
+fn run_jack(config: Arc<Config>, sampler: bool) {
+    let (client, _status) = match jack::Client::new("Forex", jack::ClientOptions::NO_START_SERVER) {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("failed to connect to JACK server: {}", err);
+            return;
+        }
+    };
+
+    let (sampler_hits_tx, _sampler_stream) = spawn_sampler_if_enabled(sampler);
 
     // Add processhandler & start client
-    let processor = Processor::new(&client);
+    let processor = match Processor::new(&client, sampler_hits_tx, config) {
+        Ok(processor) => processor,
+        Err(err) => {
+            log::error!("failed to register JACK MIDI port: {}", err);
+            return;
+        }
+    };
     let _active_client = client.activate_async((), processor, ());
 
+    wait_for_exit();
+}
+
+fn run_midir(config: Arc<Config>, sampler: bool) {
+    let gilrs_events = spawn_gilrs_poller();
+
+    let (sampler_hits_tx, _sampler_stream) = spawn_sampler_if_enabled(sampler);
+
+    let mut drums = Drums::new(Arc::clone(&config));
+    let sink = match MidirSink::new("Forex") {
+        Ok(sink) => Arc::new(Mutex::new(sink)),
+        Err(err) => {
+            log::error!("failed to open midir output: {}", err);
+            return;
+        }
+    };
+    let scheduler = Arc::new(Mutex::new(NoteScheduler::new(Arc::clone(&config))));
+
+    // midir delivers events immediately rather than on a per-frame callback,
+    // so a small timer thread emits cached note-offs as their scheduled
+    // note_off_usec arrives.
+    {
+        let sink = Arc::clone(&sink);
+        let scheduler = Arc::clone(&scheduler);
+
+        thread::spawn(move || loop {
+            scheduler.lock().unwrap().drain_due(midi::now_usec(), &mut *sink.lock().unwrap());
+            thread::sleep(std::time::Duration::from_millis(1));
+        });
+    }
+
+    while let Ok((event, time)) = gilrs_events.recv() {
+        match event {
+            gilrs::EventType::Connected => log::info!("controller connected"),
+            gilrs::EventType::Disconnected => {
+                log::info!("controller disconnected, clearing pad state");
+                drums.reset();
+                scheduler.lock().unwrap().flush_all(midi::now_usec(), &mut *sink.lock().unwrap());
+            }
+            _ => if let Some(hit) = drums.process_event(event) {
+                if let Some(sampler_hits_tx) = &sampler_hits_tx {
+                    let _ = sampler_hits_tx.try_send(hit);
+                }
+
+                let hit_usecs_ago = SystemTime::now().duration_since(time).unwrap().as_micros() as u64;
+                let now_usec = midi::now_usec().saturating_sub(hit_usecs_ago);
+                let gate_usecs = config.gate.to_usecs(config::FALLBACK_BPM);
+
+                scheduler.lock().unwrap().trigger(hit, now_usec, gate_usecs, &mut *sink.lock().unwrap());
+            },
+        }
+    }
+}
+
+fn wait_for_exit() {
     // Wait for user to input string (to not exit)
     let mut user_input = String::new();
     io::stdin().read_line(&mut user_input).ok();
 }
 
+fn main() {
+    env_logger::init();
+
+    let args = parse_args();
+    let config = Arc::new(Config::load(args.config_path.as_deref()));
+
+    match args.backend {
+        Backend::Jack => run_jack(config, args.sampler),
+        Backend::Midir => run_midir(config, args.sampler),
+    }
+}
+
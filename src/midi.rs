@@ -0,0 +1,144 @@
+// Abstracts MIDI delivery so forex isn't locked to a JACK server: `JackSink`
+// schedules bytes into the current process cycle by frame offset, `MidirSink`
+// sends them straight out over ALSA-seq/CoreMIDI via a virtual port.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::Hit;
+
+/// Somewhere to deliver raw MIDI bytes at a given microsecond timestamp. Each
+/// implementation interprets the timestamp in whatever clock domain it
+/// understands: JACK's own microsecond clock for `JackSink`, wall clock for
+/// `MidirSink`.
+pub trait MidiSink {
+    fn send(&mut self, timestamp_usec: u64, bytes: [u8; 3]);
+}
+
+/// Wall-clock "now" in microseconds, for clock domains outside JACK.
+pub fn now_usec() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+}
+
+/// Tracks one cached note-off per still-ringing tom, independent of which
+/// `MidiSink` ends up delivering it, so a retrigger can cut the old note
+/// short no matter the backend.
+pub struct NoteScheduler {
+    config: Arc<Config>,
+    cache: Vec<(u64, Hit)>,
+}
+
+impl NoteScheduler {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { config, cache: vec![] }
+    }
+
+    /// Start a new hit: cut off any still-ringing note on the same tom, send
+    /// the note-on, and cache its note-off for `gate_usecs` from now.
+    pub fn trigger(&mut self, hit: Hit, now_usec: u64, gate_usecs: u64, sink: &mut dyn MidiSink) {
+        let config = &self.config;
+
+        self.cache.retain(|(_, old_hit)| {
+            let is_same_tom = hit.tom_id == old_hit.tom_id;
+
+            if is_same_tom {
+                sink.send(now_usec, old_hit.to_midi_bytes(0x80 | config.channel, config));
+            }
+
+            !is_same_tom
+        });
+
+        sink.send(now_usec, hit.to_midi_bytes(0x90 | config.channel, config));
+        self.cache.push((now_usec.saturating_add(gate_usecs), hit));
+    }
+
+    /// Send the note-off for any cached hit whose gate has elapsed by `now_usec`.
+    pub fn drain_due(&mut self, now_usec: u64, sink: &mut dyn MidiSink) {
+        let config = &self.config;
+
+        self.cache.retain(|(note_off_usec, hit)| {
+            let due = *note_off_usec <= now_usec;
+
+            if due {
+                sink.send(now_usec, hit.to_midi_bytes(0x80 | config.channel, config));
+            }
+
+            !due
+        });
+    }
+
+    /// Immediately send the note-off for every cached hit, regardless of its
+    /// scheduled time, and empty the cache. Used on controller disconnect so
+    /// a dropped hit doesn't leave a stuck note ringing.
+    pub fn flush_all(&mut self, now_usec: u64, sink: &mut dyn MidiSink) {
+        let config = &self.config;
+
+        for (_, hit) in self.cache.drain(..) {
+            sink.send(now_usec, hit.to_midi_bytes(0x80 | config.channel, config));
+        }
+    }
+}
+
+/// Buffers outgoing bytes so the JACK process callback can write them at the
+/// right frame offset within the current cycle.
+pub struct JackSink {
+    queued: Vec<(u64, [u8; 3])>,
+}
+
+impl JackSink {
+    pub fn new() -> Self {
+        Self { queued: vec![] }
+    }
+
+    /// Write every queued message into this cycle's MIDI buffer, in
+    /// ascending frame order (JACK requires that). Anything already due gets
+    /// clamped to frame 0 rather than dropped.
+    pub fn flush(&mut self, client: &jack::Client, cycle_times: &jack::CycleTimes, writer: &mut jack::MidiWriter<'_>) {
+        let mut due: Vec<(u32, [u8; 3])> = self.queued.drain(..).map(|(usec, bytes)| {
+            let frame = if usec <= cycle_times.current_usecs {
+                0
+            } else {
+                client.time_to_frames(usec).saturating_sub(cycle_times.current_frames)
+            };
+
+            (frame, bytes)
+        }).collect();
+
+        due.sort_by_key(|(frame, _)| *frame);
+
+        for (frame, bytes) in due {
+            writer.write(&jack::RawMidi { time: frame, bytes: &bytes });
+        }
+    }
+}
+
+impl MidiSink for JackSink {
+    fn send(&mut self, timestamp_usec: u64, bytes: [u8; 3]) {
+        self.queued.push((timestamp_usec, bytes));
+    }
+}
+
+/// Delivers MIDI over a midir virtual output port.
+pub struct MidirSink {
+    conn: midir::MidiOutputConnection,
+}
+
+impl MidirSink {
+    /// Open a midir client and a virtual output port. Returns an error
+    /// (rather than panicking) if there's no usable MIDI backend — e.g. no
+    /// ALSA sequencer client in a minimal container. Virtual ports exist
+    /// only on Unix targets, so this backend does not cover WinMM.
+    pub fn new(port_name: &str) -> Result<Self, String> {
+        let midi_out = midir::MidiOutput::new("forex").map_err(|err| format!("failed to open MIDI output: {}", err))?;
+        let conn = midi_out.create_virtual(port_name).map_err(|err| format!("failed to create virtual MIDI port: {}", err))?;
+
+        Ok(Self { conn })
+    }
+}
+
+impl MidiSink for MidirSink {
+    fn send(&mut self, _timestamp_usec: u64, bytes: [u8; 3]) {
+        self.conn.send(&bytes).ok();
+    }
+}
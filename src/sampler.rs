@@ -0,0 +1,173 @@
+// Internal cpal-backed drum sampler: loads one WAV per tom at startup and
+// mixes hits down to an audio stream, so forex can make sound without an
+// external MIDI synth wired up.
+
+extern crate cpal;
+extern crate hound;
+
+use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::thread;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crossbeam_channel::Receiver;
+
+use crate::Hit;
+
+const TOM_COUNT: usize = 6;
+
+/*
+ * A single playing copy of a sample, tracking its own playback position
+ */
+struct Voice {
+    sample: Arc<[f32]>,
+    pos: usize,
+    gain: f32,
+}
+
+impl Voice {
+    // Mix this voice's next frame into `out`, returns false once exhausted
+    fn advance(&mut self, out: &mut f32) -> bool {
+        if self.pos >= self.sample.len() {
+            return false;
+        }
+
+        *out += self.sample[self.pos] * self.gain;
+        self.pos += 1;
+
+        self.pos < self.sample.len()
+    }
+}
+
+/*
+ * Mixer holds all currently playing voices and sums them per frame
+ */
+struct Mixer {
+    voices: Vec<Voice>,
+}
+
+impl Mixer {
+    fn new() -> Self {
+        Self { voices: vec![] }
+    }
+
+    fn trigger(&mut self, sample: Arc<[f32]>, gain: f32) {
+        self.voices.push(Voice { sample, pos: 0, gain });
+    }
+
+    fn next_frame(&mut self) -> f32 {
+        let mut frame = 0.0;
+        self.voices.retain_mut(|voice| voice.advance(&mut frame));
+        frame
+    }
+}
+
+// Load a mono WAV sample (float, or integer PCM scaled to -1.0..1.0) for a
+// tom from `samples/tom{id}.wav` as f32. A missing,
+// truncated, or otherwise non-decodable file just leaves that tom silent
+// rather than taking down the whole sampler.
+fn load_sample(id: u8) -> Arc<[f32]> {
+    let path = format!("samples/tom{}.wav", id);
+
+    if !Path::new(&path).exists() {
+        log::warn!("no sample found at {}, tom {} will be silent", path, id);
+        return Arc::from(Vec::new());
+    }
+
+    match try_load_sample(&path) {
+        Ok(samples) => Arc::from(samples),
+        Err(err) => {
+            log::warn!("failed to decode {} ({}), tom {} will be silent", path, err, id);
+            Arc::from(Vec::new())
+        }
+    }
+}
+
+fn try_load_sample(path: &str) -> Result<Vec<f32>, hound::Error> {
+    let mut reader = hound::WavReader::open(path)?;
+
+    match reader.spec().sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect(),
+        hound::SampleFormat::Int => {
+            let max = (1i64 << (reader.spec().bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|s| s.map(|s| s as f32 / max)).collect()
+        }
+    }
+}
+
+// Derive a mixer gain (0.0..=1.0) from a hit's raw gilrs axis velocity
+fn gain_from_velocity(velocity: f32) -> f32 {
+    (1.0 - velocity.abs()).clamp(0.0, 1.0)
+}
+
+// Fill an output buffer of any cpal sample type with successive mixer frames,
+// writing the same value to every channel of a frame
+fn write_data<T: cpal::Sample>(output: &mut [T], channels: usize, mixer: &Arc<Mutex<Mixer>>) {
+    let mut mixer = mixer.lock().unwrap();
+
+    for frame in output.chunks_mut(channels) {
+        let sample: T = cpal::Sample::from::<f32>(&mixer.next_frame());
+        for out in frame.iter_mut() {
+            *out = sample;
+        }
+    }
+}
+
+// Spawn the cpal output stream and a forwarder thread that turns incoming
+// `Hit`s into voices on the mixer. Returns the stream so the caller can keep
+// it alive for the lifetime of the program; returns an error (rather than
+// panicking) if there's no usable default output device, so a missing/odd
+// audio device doesn't take down the rest of forex with it.
+pub fn spawn(hits: Receiver<Hit>) -> Result<cpal::Stream, String> {
+    let host = cpal::default_host();
+    let device = host.default_output_device().ok_or("no default cpal output device available")?;
+    let config = device.default_output_config().map_err(|err| format!("failed to get default output config: {}", err))?;
+    let channels = config.channels() as usize;
+    let sample_format = config.sample_format();
+
+    let samples: Vec<Arc<[f32]>> = (0..TOM_COUNT as u8).map(load_sample).collect();
+    let mixer = Arc::new(Mutex::new(Mixer::new()));
+
+    let forwarder_mixer = Arc::clone(&mixer);
+    thread::spawn(move || {
+        while let Ok(hit) = hits.recv() {
+            if let Some(sample) = samples.get(hit.tom_id as usize) {
+                forwarder_mixer.lock().unwrap().trigger(Arc::clone(sample), gain_from_velocity(hit.velocity));
+            }
+        }
+    });
+
+    let stream = match sample_format {
+        cpal::SampleFormat::F32 => {
+            let stream_mixer = Arc::clone(&mixer);
+            device.build_output_stream(
+                &config.into(),
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| write_data(data, channels, &stream_mixer),
+                |err| log::error!("cpal stream error: {}", err),
+                None,
+            )
+        }
+        cpal::SampleFormat::I16 => {
+            let stream_mixer = Arc::clone(&mixer);
+            device.build_output_stream(
+                &config.into(),
+                move |data: &mut [i16], _: &cpal::OutputCallbackInfo| write_data(data, channels, &stream_mixer),
+                |err| log::error!("cpal stream error: {}", err),
+                None,
+            )
+        }
+        cpal::SampleFormat::U16 => {
+            let stream_mixer = Arc::clone(&mixer);
+            device.build_output_stream(
+                &config.into(),
+                move |data: &mut [u16], _: &cpal::OutputCallbackInfo| write_data(data, channels, &stream_mixer),
+                |err| log::error!("cpal stream error: {}", err),
+                None,
+            )
+        }
+        other => return Err(format!("unsupported cpal sample format: {:?}", other)),
+    }.map_err(|err| format!("failed to build output stream: {}", err))?;
+
+    stream.play().map_err(|err| format!("failed to start output stream: {}", err))?;
+
+    Ok(stream)
+}